@@ -0,0 +1,49 @@
+//! Compares the pre-`RawValue` dispatch path (parse the whole request into
+//! a `Value` tree, then `take`/`clone` each argument out of it) against the
+//! current borrow-based path (`Request::params` stays a `Box<RawValue>`
+//! until a handler argument is deserialized straight out of it), to back up
+//! the "avoids two allocations per call" claim behind that refactor.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use json_rpc_v2::Params;
+use serde_json::Value;
+
+const REQUEST: &[u8] = br#"{"jsonrpc":"2.0","method":"sum","params":[3,4],"id":1}"#;
+
+/// Mirrors dispatch before the `RawValue` refactor: the whole request,
+/// `params` included, is parsed into an owned `Value` tree, and each
+/// argument is taken back out of it.
+fn value_path(req: &[u8]) -> (i64, i64) {
+    let req: Value = serde_json::from_slice(req).unwrap();
+    match req["params"].clone() {
+        Value::Array(mut args) => {
+            let b = args.pop().unwrap().as_i64().unwrap();
+            let a = args.pop().unwrap().as_i64().unwrap();
+            (a, b)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// The current path: `params` is left as a `Box<RawValue>` by `Request`'s
+/// `Deserialize` impl, and each argument is parsed directly out of its
+/// slice of the original text.
+fn raw_value_path(req: &[u8]) -> (i64, i64) {
+    let req: json_rpc_v2::Request = serde_json::from_slice(req).unwrap();
+    match Params::parse(&req.params).unwrap() {
+        Params::Array(args) => {
+            let a = args[0].get().parse().unwrap();
+            let b = args[1].get().parse().unwrap();
+            (a, b)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn bench_dispatch(c: &mut Criterion) {
+    c.bench_function("dispatch/value", |b| b.iter(|| value_path(black_box(REQUEST))));
+    c.bench_function("dispatch/raw_value", |b| b.iter(|| raw_value_path(black_box(REQUEST))));
+}
+
+criterion_group!(benches, bench_dispatch);
+criterion_main!(benches);
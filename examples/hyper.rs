@@ -1,6 +1,7 @@
 use std::convert::Infallible;
 use std::error::Error;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use hyper::{Body, Request, Response, Server};
@@ -10,34 +11,25 @@ use hyper::service::{make_service_fn, service_fn};
 use serde_json::to_string;
 use tokio::fs::read_to_string;
 
-use json_rpc_v2::{json_rpc, Registry};
+use json_rpc_v2::{json_rpc, Registry, State};
 
-static mut APP: Option<App> = None;
-
-fn app() -> &'static App {
-    unsafe {
-        debug_assert!(APP.is_some());
-        APP.as_ref().unwrap()
-    }
-}
-
-struct App {
-    registry: Registry,
+struct Greeter {
+    prefix: String,
 }
 
-fn init() {
-    env_logger::init();
-
+fn registry() -> Registry {
     let mut registry = Registry::new();
     registry.register_method("greet", greet);
     registry.register::<System>();
-    unsafe { APP = Some(App { registry }); }
+    registry.data(Greeter { prefix: "Hello".to_string() });
+    registry
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    init();
+    env_logger::init();
 
+    let registry = Arc::new(registry());
     let addr = SocketAddr::from(([127, 0, 0, 1], 8888));
     println!("server started at http://{}", addr);
     println!("try:");
@@ -45,8 +37,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     println!(r#"curl http://{} -d '{{"jsonrpc":"2.0","method":"system.time","params":[],"id":1}}'"#, addr);
     println!(r#"curl http://{} -d '{{"jsonrpc":"2.0","method":"system.issue","params":[],"id":1}}'"#, addr);
 
-    let make_svc = make_service_fn(|_conn| async {
-        Ok::<_, Infallible>(service_fn(json_rpc))
+    let make_svc = make_service_fn(move |_conn| {
+        let registry = registry.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| json_rpc(registry.clone(), req))) }
     });
     let server = Server::bind(&addr).serve(make_svc);
     if let Err(e) = server.await {
@@ -55,8 +48,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn json_rpc(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    match app().registry.call(&to_bytes(req.into_body()).await?).await {
+async fn json_rpc(registry: Arc<Registry>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    match registry.call(&to_bytes(req.into_body()).await?).await {
         Some(response) => {
             let mut response = Response::new(to_string(&response).unwrap().into());
             response.headers_mut().insert("content-type", HeaderValue::from_static("application/json"));
@@ -67,8 +60,8 @@ async fn json_rpc(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
 }
 
 #[json_rpc]
-fn greet(name: String) -> Result<String, Infallible> {
-    Ok(format!("Hello {}", name))
+fn greet(State(greeter): State<Greeter>, name: String) -> Result<String, Infallible> {
+    Ok(format!("{} {}", greeter.prefix, name))
 }
 
 struct System;
@@ -82,4 +75,4 @@ impl System {
     async fn issue() -> Result<String, json_rpc_v2::Error> {
         read_to_string("/etc/issue").await.map_err(|_| json_rpc_v2::Error::server_error())
     }
-}
\ No newline at end of file
+}
@@ -1,20 +1,65 @@
 use proc_macro::TokenStream;
 
 use quote::{quote, quote_spanned};
-use syn::{Error, FnArg, ImplItem, Item, ItemFn, ItemImpl, parse, parse_macro_input, parse_str, Pat, Path, ReturnType, Signature, Token, Type};
+use syn::{Attribute, Error, FnArg, ImplItem, Item, ItemFn, ItemImpl, LitStr, parse, parse_macro_input, parse_str, Pat, Path, ReturnType, Signature, Token, Type};
+use syn::parse::Parser;
 use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 
 #[proc_macro_attribute]
-pub fn json_rpc(_attr: TokenStream, input: TokenStream) -> TokenStream {
-    match parse_macro_input!(input as Item) {
-        Item::Fn(item) => expand_fn(item).unwrap_or_else(|e| e.to_compile_error().into()),
-        Item::Impl(item) => expand_impl(item).unwrap_or_else(|e| e.to_compile_error().into()),
-        item => Error::new_spanned(item, "json_rpc: expected fn or impl block").to_compile_error().into(),
+pub fn json_rpc(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(input as Item);
+    let result = match item {
+        Item::Fn(item) => match attr.is_empty() {
+            true => expand_fn(item),
+            false => Err(Error::new(proc_macro2::Span::call_site(), "json_rpc: namespace/separator are only supported on impl blocks")),
+        },
+        Item::Impl(item) => expand_impl(item, attr),
+        item => Err(Error::new_spanned(item, "json_rpc: expected fn or impl block")),
+    };
+    result.unwrap_or_else(|e| e.to_compile_error().into())
+}
+
+/// Pulls `namespace`/`separator` out of `#[json_rpc(...)]`'s attribute
+/// arguments, e.g. `#[json_rpc(namespace = "sys", separator = "_")]`.
+fn parse_impl_attr(attr: TokenStream) -> Result<(Option<String>, Option<String>), Error> {
+    let mut namespace = None;
+    let mut separator = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("namespace") {
+            namespace = Some(meta.value()?.parse::<LitStr>()?.value());
+            Ok(())
+        } else if meta.path.is_ident("separator") {
+            separator = Some(meta.value()?.parse::<LitStr>()?.value());
+            Ok(())
+        } else {
+            Err(meta.error("json_rpc: expected `namespace` or `separator`"))
+        }
+    });
+    parser.parse(attr)?;
+    Ok((namespace, separator))
+}
+
+/// Removes and returns the `#[rpc_name("...")]` attribute (if any) from
+/// `attrs`, so it doesn't leak into the generated method's own attributes.
+fn take_rpc_name(attrs: &mut Vec<Attribute>) -> Result<Option<String>, Error> {
+    let mut name = None;
+    let mut i = 0;
+    while i < attrs.len() {
+        if attrs[i].path().is_ident("rpc_name") {
+            let attr = attrs.remove(i);
+            if name.is_some() {
+                return Err(Error::new_spanned(attr, "json_rpc: duplicate #[rpc_name(...)]"));
+            }
+            name = Some(attr.parse_args::<LitStr>()?.value());
+        } else {
+            i += 1;
+        }
     }
+    Ok(name)
 }
 
-fn expand_impl(mut item: ItemImpl) -> Result<TokenStream, Error> {
+fn expand_impl(mut item: ItemImpl, attr: TokenStream) -> Result<TokenStream, Error> {
     if item.trait_.is_some() {
         return Err(syn::Error::new_spanned(item.trait_.unwrap().1, "json_rpc: trait impl is not supported"));
     }
@@ -22,16 +67,28 @@ fn expand_impl(mut item: ItemImpl) -> Result<TokenStream, Error> {
         return Err(syn::Error::new_spanned(item.generics, "json_rpc: generic is not supported"));
     }
 
-    let prefix = match *item.self_ty {
-        Type::Path(ref path) => path.path.segments.last().unwrap().ident.to_string().to_ascii_lowercase(),
-        _ => return Err(syn::Error::new_spanned(item.self_ty, "json_rpc: not supported")),
+    let (namespace, separator) = parse_impl_attr(attr)?;
+    let separator = separator.unwrap_or_else(|| ".".to_string());
+    let prefix = match namespace {
+        Some(namespace) => namespace,
+        None => match *item.self_ty {
+            Type::Path(ref path) => path.path.segments.last().unwrap().ident.to_string().to_ascii_lowercase(),
+            _ => return Err(syn::Error::new_spanned(item.self_ty, "json_rpc: not supported")),
+        },
     };
 
-    let mut names = Vec::new();
+    let mut names: Vec<String> = Vec::new();
     let mut func: Vec<Path> = Vec::new();
     for impl_item in &mut item.items {
         if let ImplItem::Fn(impl_item) = impl_item {
-            names.push(format!("{}.{}", prefix, impl_item.sig.ident));
+            let name = match take_rpc_name(&mut impl_item.attrs)? {
+                Some(name) => name,
+                None => format!("{}{}{}", prefix, separator, impl_item.sig.ident),
+            };
+            if names.contains(&name) {
+                return Err(syn::Error::new_spanned(&impl_item.sig.ident, format!("json_rpc: duplicate method name `{}`", name)));
+            }
+            names.push(name);
             func.push(parse_str(&format!("Self::{}", impl_item.sig.ident))?);
             *impl_item = parse(expand_fn(ItemFn {
                 attrs: impl_item.attrs.clone(),
@@ -61,11 +118,26 @@ fn expand_fn(item: ItemFn) -> Result<TokenStream, Error> {
     }
 
     let ret_assert = gen_ret_assert(&item.sig)?;
-    let (arg_assert, args) = gen_arg_assert(&item.sig.inputs)?;
+    let (arg_assert, arg_kinds) = gen_arg_assert(&item.sig.inputs)?;
     let wait = item.sig.asyncness.map(|_| quote!(let result = result.await;));
-    let argc = 0..args.len();
+
+    let mut data_pos = 0usize;
+    let call_args_by_position: Vec<_> = arg_kinds.iter().map(|arg| match arg {
+        ArgKind::Data(_) => {
+            let i = data_pos;
+            data_pos += 1;
+            quote!(arg!(args.get(#i).map(|v| v.get()).unwrap_or("null"))?)
+        }
+        ArgKind::State(ty) => quote!(json_rpc_v2::State(state_value!(#ty)?)),
+    }).collect();
+
+    let call_args_by_name: Vec<_> = arg_kinds.iter().map(|arg| match arg {
+        ArgKind::Data(name) => quote!(arg!(args.get(#name).map(|v| v.get()).unwrap_or("null"))?),
+        ArgKind::State(ty) => quote!(json_rpc_v2::State(state_value!(#ty)?)),
+    }).collect();
+
     let gen = quote! {
-        #vis fn #ident(args: json_rpc_v2::serde_json::Value) -> std::pin::Pin<Box<dyn std::future::Future<Output=std::result::Result<json_rpc_v2::serde_json::Value, json_rpc_v2::Error>> + Send>> {
+        #vis fn #ident(state: std::sync::Arc<json_rpc_v2::StateMap>, args: Box<json_rpc_v2::serde_json::value::RawValue>) -> std::pin::Pin<Box<dyn std::future::Future<Output=std::result::Result<json_rpc_v2::serde_json::Value, json_rpc_v2::Error>> + Send>> {
             #ret_assert
             #arg_assert
             #item
@@ -73,25 +145,32 @@ fn expand_fn(item: ItemFn) -> Result<TokenStream, Error> {
             #[allow(unused)]
             macro_rules! arg {
                 ($v:expr) => {
-                    json_rpc_v2::serde_json::from_value($v).map_err(|err| {
+                    json_rpc_v2::serde_json::from_str($v).map_err(|err| {
                         json_rpc_v2::error!("deserialize parameter error: {}", err);
                         json_rpc_v2::Error::invalid_params()
                     })
                 };
             }
 
+            #[allow(unused)]
+            macro_rules! state_value {
+                ($ty:ty) => {
+                    state.get::<$ty>().ok_or_else(json_rpc_v2::Error::internal_error)
+                };
+            }
+
             Box::pin(async move {
-                let result = match args {
-                    json_rpc_v2::serde_json::Value::Array(mut args) => {
-                        #ident(#(arg!(args.get_mut(#argc).map(json_rpc_v2::serde_json::Value::take).unwrap_or(json_rpc_v2::serde_json::Value::Null))?),*)
+                let result = match json_rpc_v2::Params::parse(&args)? {
+                    json_rpc_v2::Params::Array(args) => {
+                        #ident(#(#call_args_by_position),*)
                     }
-                    json_rpc_v2::serde_json::Value::Object(mut args) => {
-                        #ident(#(arg!(args.remove(#args).unwrap_or(json_rpc_v2::serde_json::Value::Null))?),*)
+                    json_rpc_v2::Params::Object(args) => {
+                        #ident(#(#call_args_by_name),*)
                     }
-                    _ => return Err(json_rpc_v2::Error::invalid_params()),
                 };
                 #wait
-                Ok(json_rpc_v2::serde_json::to_value(result?).expect("serialize error"))
+                let result = result.map_err(json_rpc_v2::IntoRpcError::into_rpc_error)?;
+                Ok(json_rpc_v2::serde_json::to_value(result).expect("serialize error"))
             })
         }
     };
@@ -103,29 +182,68 @@ fn gen_ret_assert(sig: &Signature) -> Result<proc_macro2::TokenStream, Error> {
         ReturnType::Default => Err(Error::new_spanned(sig, "json rpc: expected return value")),
         ReturnType::Type(_, ref ty) => Ok(quote_spanned! {ty.span()=>
             {
-                fn assert(_: Option<std::result::Result<impl json_rpc_v2::serde::Serialize, impl Into<json_rpc_v2::Error>>>) {}
+                fn assert(_: Option<std::result::Result<impl json_rpc_v2::serde::Serialize, impl json_rpc_v2::IntoRpcError>>) {}
                 assert(None::<#ty>);
             }
         }),
     }
 }
 
-fn gen_arg_assert(inputs: &Punctuated<FnArg, Token![,]>) -> Result<(proc_macro2::TokenStream, Vec<String>), Error> {
+enum ArgKind {
+    /// A plain argument, deserialized from the request params by position
+    /// or by name. Carries the argument's identifier for the by-name path.
+    Data(String),
+    /// A `State<T>` argument, resolved from the registry's `StateMap`
+    /// instead of from the request params. Carries `T`.
+    State(Type),
+}
+
+/// Returns the inner `T` of a `State<T>` type, or `None` if `ty` is not `State<...>`.
+fn state_inner_type(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(path) => path,
+        _ => return None,
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "State" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => match args.args.first() {
+            Some(syn::GenericArgument::Type(ty)) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn gen_arg_assert(inputs: &Punctuated<FnArg, Token![,]>) -> Result<(proc_macro2::TokenStream, Vec<ArgKind>), Error> {
     let mut assert = quote!();
     let mut args = Vec::with_capacity(inputs.len());
     for arg in inputs {
         match arg {
-            FnArg::Typed(arg) => match *arg.pat {
-                Pat::Ident(ref pat) => {
-                    args.push(pat.ident.to_string());
-                    let ty = &arg.ty;
-                    assert = quote_spanned! {ty.span()=>
-                        #assert
-                        { struct _Assert where #ty: json_rpc_v2::serde::de::DeserializeOwned; }
-                    };
+            FnArg::Typed(arg) => {
+                let ty = &arg.ty;
+                match state_inner_type(ty) {
+                    Some(inner) => {
+                        assert = quote_spanned! {inner.span()=>
+                            #assert
+                            { struct _Assert where #inner: std::any::Any + Send + Sync; }
+                        };
+                        args.push(ArgKind::State(inner.clone()));
+                    }
+                    None => match *arg.pat {
+                        Pat::Ident(ref pat) => {
+                            assert = quote_spanned! {ty.span()=>
+                                #assert
+                                { struct _Assert where #ty: json_rpc_v2::serde::de::DeserializeOwned; }
+                            };
+                            args.push(ArgKind::Data(pat.ident.to_string()));
+                        }
+                        _ => return Err(Error::new_spanned(arg, "json_rpc: unsupported argument")),
+                    },
                 }
-                _ => return Err(Error::new_spanned(arg, "json_rpc: unsupported argument")),
-            },
+            }
             FnArg::Receiver(arg) => return Err(Error::new_spanned(arg, "json_rpc: method is not supported")),
         }
     }
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+use crate::{Error, Id, Request, Response};
+
+/// Builds `Request` frames with auto-incrementing ids and matches
+/// responses back to the call that sent them, in any order.
+pub struct Client {
+    next_id: AtomicI64,
+    pending: Mutex<HashMap<Id, oneshot::Sender<Response>>>,
+    outbound: UnboundedSender<Value>,
+}
+
+impl Client {
+    pub fn new() -> (Client, UnboundedReceiver<Value>) {
+        let (outbound, rx) = unbounded_channel();
+        let client = Self {
+            next_id: AtomicI64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            outbound,
+        };
+        (client, rx)
+    }
+
+    fn next_id(&self) -> Id {
+        Id::Number(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn register(&self, id: Id) -> oneshot::Receiver<Response> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        rx
+    }
+
+    pub async fn request<P: Serialize, R: DeserializeOwned>(&self, method: impl Into<String>, params: P) -> Result<R, Error> {
+        let id = self.next_id();
+        let rx = self.register(id.clone());
+        if !self.send(Request::new(method.into(), to_params(params)?, id.clone())) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(Error::internal_error());
+        }
+        response_into_result(rx.await.map_err(|_| Error::internal_error())?)
+    }
+
+    pub fn notify<P: Serialize>(&self, method: impl Into<String>, params: P) -> Result<(), Error> {
+        self.send(Request::new(method.into(), to_params(params)?, Id::Notification));
+        Ok(())
+    }
+
+    pub fn batch(&self) -> Batch<'_> {
+        Batch { client: self, requests: Vec::new(), slots: Vec::new() }
+    }
+
+    fn send(&self, req: Request) -> bool {
+        self.outbound.send(serde_json::to_value(&req).expect("serialize error")).is_ok()
+    }
+
+    pub fn handle_response(&self, data: &[u8]) {
+        match data {
+            [b'[', ..] => match serde_json::from_slice::<Vec<Response>>(data) {
+                Ok(responses) => responses.into_iter().for_each(|response| self.resolve(response)),
+                Err(err) => error!("parse batch response error: {}", err),
+            },
+            _ => match serde_json::from_slice::<Response>(data) {
+                Ok(response) => self.resolve(response),
+                Err(err) => error!("parse response error: {}", err),
+            },
+        }
+    }
+
+    fn resolve(&self, response: Response) {
+        if response.id == Id::Null {
+            if let Some(error) = response.error {
+                for (_, tx) in self.pending.lock().unwrap().drain() {
+                    let _ = tx.send(Response::error(Id::Null, error.clone()));
+                }
+            }
+            return;
+        }
+        if let Some(tx) = self.pending.lock().unwrap().remove(&response.id) {
+            let _ = tx.send(response);
+        }
+    }
+}
+
+fn to_params<P: Serialize>(params: P) -> Result<Box<RawValue>, Error> {
+    serde_json::value::to_raw_value(&params).map_err(|err| {
+        error!("serialize parameter error: {}", err);
+        Error::invalid_params()
+    })
+}
+
+fn response_into_result<R: DeserializeOwned>(response: Response) -> Result<R, Error> {
+    match response.error {
+        Some(err) => Err(err),
+        None => serde_json::from_value(response.result.unwrap_or(Value::Null)).map_err(|err| {
+            error!("deserialize result error: {}", err);
+            Error::internal_error()
+        }),
+    }
+}
+
+enum Slot {
+    Pending(oneshot::Receiver<Response>),
+    Failed(Error),
+}
+
+pub struct Batch<'a> {
+    client: &'a Client,
+    requests: Vec<Request>,
+    slots: Vec<Slot>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn call<P: Serialize>(mut self, method: impl Into<String>, params: P) -> Self {
+        match to_params(params) {
+            Ok(params) => {
+                let id = self.client.next_id();
+                let rx = self.client.register(id.clone());
+                self.requests.push(Request::new(method.into(), params, id));
+                self.slots.push(Slot::Pending(rx));
+            }
+            Err(err) => self.slots.push(Slot::Failed(err)),
+        }
+        self
+    }
+
+    pub async fn send(self) -> Vec<Result<Value, Error>> {
+        let ids: Vec<Id> = self.requests.iter().map(|req| req.id.clone()).collect();
+        let sent = self.requests.is_empty() || {
+            let payload = serde_json::to_value(&self.requests).expect("serialize error");
+            self.client.outbound.send(payload).is_ok()
+        };
+        if !sent {
+            for id in &ids {
+                self.client.pending.lock().unwrap().remove(id);
+            }
+        }
+
+        let mut results = Vec::with_capacity(self.slots.len());
+        for slot in self.slots {
+            results.push(match slot {
+                Slot::Failed(err) => Err(err),
+                Slot::Pending(_) if !sent => Err(Error::internal_error()),
+                Slot::Pending(rx) => match rx.await {
+                    Ok(response) => response_into_result(response),
+                    Err(_) => Err(Error::internal_error()),
+                },
+            });
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::runtime::Builder;
+
+    use crate::{Client, Error, Id, Response};
+
+    #[test]
+    fn test_request() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (client, mut outbound) = Client::new();
+            let call = client.request::<_, i64>("sum", json!([3, 4]));
+            let reply = async {
+                let sent = outbound.recv().await.unwrap();
+                assert_eq!(sent["method"], "sum");
+                assert_eq!(sent["params"], json!([3, 4]));
+                let id: Id = serde_json::from_value(sent["id"].clone()).unwrap();
+                client.handle_response(&serde_json::to_vec(&Response::ok(id, json!(7))).unwrap());
+            };
+
+            let (result, _) = tokio::join!(call, reply);
+            assert_eq!(result.unwrap(), 7);
+        });
+    }
+
+    #[test]
+    fn test_notify_omits_id() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (client, mut outbound) = Client::new();
+            client.notify("ping", json!([])).unwrap();
+            let sent = outbound.recv().await.unwrap();
+            assert!(sent.get("id").is_none());
+        });
+    }
+
+    #[test]
+    fn test_request_error() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (client, mut outbound) = Client::new();
+            let call = client.request::<_, i64>("sum", json!([3, 4]));
+            let reply = async {
+                let sent = outbound.recv().await.unwrap();
+                let id: Id = serde_json::from_value(sent["id"].clone()).unwrap();
+                client.handle_response(&serde_json::to_vec(&Response::error(id, Error::invalid_params())).unwrap());
+            };
+
+            let (result, _) = tokio::join!(call, reply);
+            assert_eq!(result.unwrap_err().code, -32602);
+        });
+    }
+
+    #[test]
+    fn test_batch() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (client, mut outbound) = Client::new();
+            let batch = client.batch().call("sum", json!([1, 2])).call("sum", json!([3, 4])).send();
+            let reply = async {
+                let sent = outbound.recv().await.unwrap();
+                let ids: Vec<Id> = sent.as_array().unwrap().iter()
+                    .map(|req| serde_json::from_value(req["id"].clone()).unwrap())
+                    .collect();
+                let responses = vec![Response::ok(ids[0].clone(), json!(3)), Response::ok(ids[1].clone(), json!(7))];
+                client.handle_response(&serde_json::to_vec(&responses).unwrap());
+            };
+
+            let (results, _) = tokio::join!(batch, reply);
+            assert_eq!(results[0].as_ref().unwrap(), &json!(3));
+            assert_eq!(results[1].as_ref().unwrap(), &json!(7));
+        });
+    }
+
+    #[test]
+    fn test_batch_level_failure_fails_pending_calls() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (client, mut outbound) = Client::new();
+            let call = client.request::<_, i64>("sum", json!([3, 4]));
+            let reply = async {
+                outbound.recv().await.unwrap();
+                client.handle_response(&serde_json::to_vec(&Response::error(Id::Null, Error::parse_error())).unwrap());
+            };
+
+            let (result, _) = tokio::join!(call, reply);
+            assert_eq!(result.unwrap_err().code, -32700);
+        });
+    }
+
+    #[test]
+    fn test_batch_call_with_unserializable_params_is_not_sent() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (client, mut outbound) = Client::new();
+            // serde_json rejects map keys that are not strings.
+            let unserializable = std::collections::HashMap::from([(vec![1], 1)]);
+            let batch = client.batch().call("sum", unserializable).call("sum", json!([1, 2])).send();
+            let reply = async {
+                let sent = outbound.recv().await.unwrap();
+                // Only the serializable call should have gone out.
+                assert_eq!(sent.as_array().unwrap().len(), 1);
+                let ids: Vec<Id> = sent.as_array().unwrap().iter()
+                    .map(|req| serde_json::from_value(req["id"].clone()).unwrap())
+                    .collect();
+                let responses = vec![Response::ok(ids[0].clone(), json!(3))];
+                client.handle_response(&serde_json::to_vec(&responses).unwrap());
+            };
+
+            let (results, _) = tokio::join!(batch, reply);
+            assert_eq!(results[0].as_ref().unwrap_err().code, -32602);
+            assert_eq!(results[1].as_ref().unwrap(), &json!(3));
+        });
+    }
+}
@@ -0,0 +1,63 @@
+use crate::Error;
+
+/// Converts a handler's error type into a fully populated [`Error`],
+/// letting application code surface its own `code`/`data` to clients
+/// instead of every failure being flattened to `Error::server_error()`.
+///
+/// Implemented for anything already convertible via `Into<Error>` (so
+/// existing handlers that return `Error` or `Infallible` keep working
+/// unchanged). With the `easy-errors` feature enabled, it is instead
+/// blanket-implemented for any `E: Display`, turning the `Display` output
+/// into the error `message` with code `-32000` — handy for application
+/// error enums that don't want to hand-write an `Into<Error>` conversion.
+pub trait IntoRpcError {
+    fn into_rpc_error(self) -> Error;
+}
+
+#[cfg(not(feature = "easy-errors"))]
+impl<E: Into<Error>> IntoRpcError for E {
+    fn into_rpc_error(self) -> Error {
+        self.into()
+    }
+}
+
+#[cfg(feature = "easy-errors")]
+impl<E: std::fmt::Display> IntoRpcError for E {
+    fn into_rpc_error(self) -> Error {
+        Error::custom(-32000, self.to_string())
+    }
+}
+
+// `Error` doesn't implement `Display` (its `message` is already structured),
+// so the blanket impl above doesn't cover it. Handlers returning
+// `Result<_, Error>` directly (e.g. `System::time`) must keep compiling
+// whether or not `easy-errors` is enabled.
+#[cfg(feature = "easy-errors")]
+impl IntoRpcError for Error {
+    fn into_rpc_error(self) -> Error {
+        self
+    }
+}
+
+#[cfg(all(test, feature = "easy-errors"))]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct MyError;
+
+    impl fmt::Display for MyError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "boom")
+        }
+    }
+
+    #[test]
+    fn test_display_error_converts() {
+        let err = MyError.into_rpc_error();
+        assert_eq!(err.code, -32000);
+        assert_eq!(err.message, "boom");
+    }
+}
@@ -1,10 +1,26 @@
+// `#[json_rpc]`-generated code refers to this crate by name (`json_rpc_v2::...`),
+// which only resolves for external consumers. This alias lets our own
+// `#[cfg(test)]` code exercise the macro the same way they do.
+#[cfg(test)]
+extern crate self as json_rpc_v2;
+
 pub use log::error;
 pub use serde;
 pub use serde_json;
 
+pub use client::*;
+pub use error::*;
 pub use json_rpc_v2_macro::json_rpc;
+pub use middleware::*;
 pub use protocol::*;
 pub use registry::*;
+pub use state::*;
+pub use subscription::*;
 
+mod client;
+mod error;
+mod middleware;
 mod protocol;
-mod registry;
\ No newline at end of file
+mod registry;
+mod state;
+mod subscription;
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{error, info};
+use serde_json::{from_str, Value};
+use tokio::spawn;
+
+use crate::{Connection, Error, Method, Request, Response, StateMap, SubscribeMethod, SubscriptionId};
+
+pub trait Middleware: Send + Sync {
+    fn on_call<'a>(&'a self, req: Request, next: Next) -> Pin<Box<dyn Future<Output=Option<Response>> + Send + 'a>>;
+}
+
+pub(crate) enum Terminal {
+    Call(Option<Method>),
+    Subscribe { connection: Arc<Connection>, notify_method: &'static str, method: SubscribeMethod },
+    Unsubscribe { connection: Arc<Connection> },
+}
+
+pub struct Next {
+    pub(crate) middlewares: Arc<Vec<Arc<dyn Middleware>>>,
+    pub(crate) index: usize,
+    pub(crate) state: Arc<StateMap>,
+    pub(crate) terminal: Terminal,
+}
+
+impl Next {
+    pub async fn run(self, req: Request) -> Option<Response> {
+        match self.middlewares.get(self.index).cloned() {
+            Some(middleware) => {
+                let next = Next { index: self.index + 1, ..self };
+                middleware.on_call(req, next).await
+            }
+            None => dispatch(self.state, self.terminal, req).await,
+        }
+    }
+}
+
+pub(crate) async fn dispatch(state: Arc<StateMap>, terminal: Terminal, req: Request) -> Option<Response> {
+    match terminal {
+        Terminal::Call(method) => dispatch_call(method, state, req).await,
+        Terminal::Subscribe { connection, notify_method, method } => dispatch_subscribe(&connection, notify_method, method, state, req),
+        Terminal::Unsubscribe { connection } => dispatch_unsubscribe(&connection, req),
+    }
+}
+
+async fn dispatch_call(method: Option<Method>, state: Arc<StateMap>, req: Request) -> Option<Response> {
+    match method {
+        Some(method) => match method(state, req.params).await {
+            Ok(result) if !req.id.is_notification() => Some(Response::ok(req.id, result)),
+            Err(err) if !req.id.is_notification() => Some(Response::error(req.id, err)),
+            _ => None,
+        },
+        None if !req.id.is_notification() => Some(Response::error(req.id, Error::method_not_found())),
+        None => None,
+    }
+}
+
+fn dispatch_subscribe(connection: &Arc<Connection>, notify_method: &'static str, method: SubscribeMethod, state: Arc<StateMap>, req: Request) -> Option<Response> {
+    let params: Value = match from_str(req.params.get()) {
+        Ok(params) => params,
+        Err(err) => {
+            error!("deserialize subscribe params error: {}", err);
+            return (!req.id.is_notification()).then(|| Response::error(req.id, Error::invalid_params()));
+        }
+    };
+
+    let (id, sink) = connection.new_subscription(notify_method);
+    let task = spawn(async move {
+        if let Err(err) = method(state, params, sink).await {
+            error!("subscription {} error: {}", notify_method, err.message);
+        }
+    });
+    connection.track(id, task);
+
+    if req.id.is_notification() {
+        None
+    } else {
+        Some(Response::ok(req.id, serde_json::to_value(id).expect("serialize error")))
+    }
+}
+
+fn dispatch_unsubscribe(connection: &Arc<Connection>, req: Request) -> Option<Response> {
+    let id: SubscriptionId = match from_str(req.params.get()) {
+        Ok(id) => id,
+        Err(err) => {
+            error!("deserialize subscription id error: {}", err);
+            return (!req.id.is_notification()).then(|| Response::error(req.id, Error::invalid_params()));
+        }
+    };
+    let removed = connection.unsubscribe(id);
+    if req.id.is_notification() {
+        None
+    } else {
+        Some(Response::ok(req.id, serde_json::to_value(removed).expect("serialize error")))
+    }
+}
+
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn on_call<'a>(&'a self, req: Request, next: Next) -> Pin<Box<dyn Future<Output=Option<Response>> + Send + 'a>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let method = req.method.clone();
+            let response = next.run(req).await;
+            info!("{} took {:?}", method, start.elapsed());
+            response
+        })
+    }
+}
@@ -4,6 +4,7 @@ use std::convert::Infallible;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::Unexpected;
 use serde_json::Value;
+use serde_json::value::RawValue;
 
 #[derive(Debug, Copy, Clone)]
 struct V2_0;
@@ -42,24 +43,32 @@ impl Id {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Request {
     #[allow(unused)]
     jsonrpc: V2_0,
     pub method: String,
-    pub params: Value,
-    #[serde(default)]
+    /// Left unparsed until a method is resolved, so dispatch never builds
+    /// an intermediate `Value` tree for params it might not even need.
+    pub params: Box<RawValue>,
+    #[serde(default, skip_serializing_if = "Id::is_notification")]
     pub id: Id,
 }
 
-#[derive(Serialize)]
+impl Request {
+    pub fn new(method: String, params: Box<RawValue>, id: Id) -> Self {
+        Self { jsonrpc: V2_0, method, params, id }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Response {
     jsonrpc: V2_0,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub result: Option<Value>,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub error: Option<Error>,
 
     pub id: Id,
@@ -77,7 +86,7 @@ impl Response {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Debug)]
 pub struct Error {
     pub code: i32,
     pub message: Cow<'static, str>,
@@ -86,6 +95,20 @@ pub struct Error {
     pub data: Option<Value>,
 }
 
+impl<'de> Deserialize<'de> for Error {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        #[derive(Deserialize)]
+        struct ErrorData {
+            code: i32,
+            message: String,
+            #[serde(default)]
+            data: Option<Value>,
+        }
+        let error = ErrorData::deserialize(deserializer)?;
+        Ok(Self { code: error.code, message: Cow::Owned(error.message), data: error.data })
+    }
+}
+
 impl From<Infallible> for Error {
     fn from(value: Infallible) -> Self {
         match value {}
@@ -140,4 +163,14 @@ impl Error {
             data: None,
         }
     }
+
+    /// Builds an application-defined error with a custom `code` and `message`.
+    pub fn custom(code: i32, message: impl Into<Cow<'static, str>>) -> Self {
+        Self { code, message: message.into(), data: None }
+    }
+
+    /// Like [`Error::custom`], with an additional `data` payload.
+    pub fn with_data(code: i32, message: impl Into<Cow<'static, str>>, data: Value) -> Self {
+        Self { code, message: message.into(), data: Some(data) }
+    }
 }
@@ -1,16 +1,48 @@
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use log::error;
 use serde::Serialize;
-use serde_json::{from_slice, Value};
+use serde_json::value::RawValue;
+use serde_json::{from_slice, from_str, Value};
 use tokio::spawn;
 use tokio::sync::mpsc::unbounded_channel;
 
-use crate::{Error, Id, Request, Response};
+use crate::middleware::{dispatch, Terminal};
+use crate::{Connection, Error, Id, Middleware, Next, Request, Response, StateMap, SubscribeMethod};
 
-pub type Method = fn(Value) -> Pin<Box<dyn Future<Output=Result<Value, Error>> + Send>>;
+pub type Method = fn(Arc<StateMap>, Box<RawValue>) -> Pin<Box<dyn Future<Output=Result<Value, Error>> + Send>>;
+
+/// A params payload split into its by-position or by-name view, without
+/// ever materializing a full `Value` tree: each element stays as a borrowed
+/// [`RawValue`] slice of the original request text until a handler
+/// argument actually needs to deserialize it.
+pub enum Params<'a> {
+    Array(Vec<&'a RawValue>),
+    Object(HashMap<&'a str, &'a RawValue>),
+}
+
+impl<'a> Params<'a> {
+    /// Parses `raw` into whichever of [`Params::Array`]/[`Params::Object`]
+    /// its outermost token is; anything else (a bare number, string, etc.)
+    /// is not a valid params container and is rejected up front.
+    pub fn parse(raw: &'a RawValue) -> Result<Self, Error> {
+        match raw.get().trim_start().as_bytes().first() {
+            Some(b'[') => from_str(raw.get()).map(Params::Array).map_err(|err| {
+                error!("deserialize params error: {}", err);
+                Error::invalid_params()
+            }),
+            Some(b'{') => from_str(raw.get()).map(Params::Object).map_err(|err| {
+                error!("deserialize params error: {}", err);
+                Error::invalid_params()
+            }),
+            _ => Err(Error::invalid_params()),
+        }
+    }
+}
 
 pub trait Provider {
     fn methods() -> &'static [(&'static str, Method)];
@@ -18,11 +50,21 @@ pub trait Provider {
 
 pub struct Registry {
     methods: HashMap<&'static str, Method>,
+    subscriptions: HashMap<&'static str, (&'static str, SubscribeMethod)>,
+    unsubscribe_methods: HashSet<&'static str>,
+    state: Arc<StateMap>,
+    middlewares: Arc<Vec<Arc<dyn Middleware>>>,
 }
 
 impl Registry {
     pub fn new() -> Registry {
-        Self { methods: HashMap::new() }
+        Self {
+            methods: HashMap::new(),
+            subscriptions: HashMap::new(),
+            unsubscribe_methods: HashSet::new(),
+            state: Arc::new(StateMap::default()),
+            middlewares: Arc::new(Vec::new()),
+        }
     }
 
     pub fn register<T: Provider>(&mut self) {
@@ -35,6 +77,36 @@ impl Registry {
         self.methods.insert(name, method);
     }
 
+    /// Only meaningful on connections driven through a `ConnectionDriver`;
+    /// calling `name` through the plain `Registry::call` HTTP path fails
+    /// with `method not found`.
+    pub fn register_subscription(&mut self, name: &'static str, notify_method: &'static str, method: SubscribeMethod) {
+        self.subscriptions.insert(name, (notify_method, method));
+    }
+
+    pub fn register_unsubscribe(&mut self, name: &'static str) {
+        self.unsubscribe_methods.insert(name);
+    }
+
+    /// Makes `value` available to handler arguments of type `State<T>`.
+    /// Panics if called after the registry has been shared.
+    pub fn data<T: Any + Send + Sync>(&mut self, value: T) -> &mut Self {
+        Arc::get_mut(&mut self.state)
+            .expect("Registry::data called after the registry was shared")
+            .insert(value);
+        self
+    }
+
+    /// The first middleware registered is the outermost: it sees the
+    /// request first and the response last. Panics if called after
+    /// the registry has been shared, same as `Registry::data`.
+    pub fn register_middleware(&mut self, middleware: Arc<dyn Middleware>) -> &mut Self {
+        Arc::get_mut(&mut self.middlewares)
+            .expect("Registry::register_middleware called after the registry was shared")
+            .push(middleware);
+        self
+    }
+
     pub async fn call(&self, req: &[u8]) -> Option<Value> {
         match req {
             [b'{', ..] => match from_slice(req) {
@@ -64,6 +136,33 @@ impl Registry {
         }
     }
 
+    /// Like `call`, but subscribe/unsubscribe methods are resolved against
+    /// `connection` instead of failing with method not found. Batch
+    /// requests are not subscription-aware and are handled as in `call`.
+    pub async fn call_with_connection(&self, connection: &Arc<Connection>, req: &[u8]) -> Option<Value> {
+        match req {
+            [b'{', ..] => match from_slice(req) {
+                Ok(req) => self.call_one_with_connection(connection, req).await.map(to_value),
+                Err(err) => {
+                    error!("parse request error: {}", err);
+                    Some(parse_error())
+                }
+            }
+            _ => self.call(req).await,
+        }
+    }
+
+    async fn call_one_with_connection(&self, connection: &Arc<Connection>, req: Request) -> Option<Response> {
+        let terminal = if let Some(&(notify_method, method)) = self.subscriptions.get(req.method.as_str()) {
+            Terminal::Subscribe { connection: connection.clone(), notify_method, method }
+        } else if self.unsubscribe_methods.contains(req.method.as_str()) {
+            Terminal::Unsubscribe { connection: connection.clone() }
+        } else {
+            Terminal::Call(self.get_method(&req.method))
+        };
+        self.run_chain(terminal, req).await
+    }
+
     fn get_method(&self, method: &str) -> Option<Method> {
         match self.methods.get(method) {
             Some(method) => Some(*method),
@@ -74,16 +173,22 @@ impl Registry {
         }
     }
 
-    async fn call_one(&self, req: Request) -> Option<Response> {
-        match self.get_method(&req.method) {
-            Some(method) => match method(req.params).await {
-                Ok(result) if !req.id.is_notification() => Some(Response::ok(req.id, result)),
-                Err(err) if !req.id.is_notification() => Some(Response::error(req.id, err)),
-                _ => None,
-            },
-            None if !req.id.is_notification() => Some(Response::error(req.id, Error::method_not_found())),
-            None => None,
+    async fn run_chain(&self, terminal: Terminal, req: Request) -> Option<Response> {
+        if self.middlewares.is_empty() {
+            return dispatch(self.state.clone(), terminal, req).await;
         }
+        let next = Next {
+            middlewares: self.middlewares.clone(),
+            index: 0,
+            state: self.state.clone(),
+            terminal,
+        };
+        next.run(req).await
+    }
+
+    async fn call_one(&self, req: Request) -> Option<Response> {
+        let terminal = Terminal::Call(self.get_method(&req.method));
+        self.run_chain(terminal, req).await
     }
 
     async fn call_batch(&self, batch_req: Vec<Request>) -> Vec<Response> {
@@ -92,22 +197,40 @@ impl Registry {
         let (tx, mut rx) = unbounded_channel();
 
         for req in batch_req {
-            match self.get_method(&req.method) {
-                Some(method) if !req.id.is_notification() => {
+            let terminal = Terminal::Call(self.get_method(&req.method));
+
+            if self.middlewares.is_empty() {
+                let state = self.state.clone();
+                if !req.id.is_notification() {
                     wait += 1;
                     let tx = tx.clone();
                     let _ = spawn(async move {
-                        tx.send(match method(req.params).await {
-                            Ok(result) => Response::ok(req.id, result),
-                            Err(err) => Response::error(req.id, err),
-                        })
+                        if let Some(response) = dispatch(state, terminal, req).await {
+                            let _ = tx.send(response);
+                        }
                     });
+                } else {
+                    let _ = spawn(async move { dispatch(state, terminal, req).await; });
                 }
-                Some(method) => {
-                    let _ = spawn(async move { method(req.params); });
-                }
-                None if !req.id.is_notification() => response.push(Response::error(req.id, Error::method_not_found())),
-                None => {}
+                continue;
+            }
+
+            let next = Next {
+                middlewares: self.middlewares.clone(),
+                index: 0,
+                state: self.state.clone(),
+                terminal,
+            };
+            if !req.id.is_notification() {
+                wait += 1;
+                let tx = tx.clone();
+                let _ = spawn(async move {
+                    if let Some(response) = next.run(req).await {
+                        let _ = tx.send(response);
+                    }
+                });
+            } else {
+                let _ = spawn(async move { next.run(req).await; });
             }
         }
 
@@ -137,14 +260,16 @@ fn to_value(v: impl Serialize) -> Value {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::Infallible;
     use std::future::Future;
     use std::pin::Pin;
-    use std::sync::Once;
+    use std::sync::{Arc, Once};
 
+    use serde_json::value::RawValue;
     use serde_json::{to_string, Value};
     use tokio::runtime::{Builder, Runtime};
 
-    use crate::{Error, Registry};
+    use crate::{json_rpc, Error, Middleware, Next, Params, Registry, Request, Response, State, StateMap};
 
     static mut ENV: Option<Env> = None;
     static ONCE: Once = Once::new();
@@ -160,34 +285,50 @@ mod tests {
             let runtime = Builder::new_current_thread().build().unwrap();
             let mut registry = Registry::new();
             registry.register_method("sum", sum);
+            registry.register_method("offsetSum", offset_sum);
+            registry.data(Offset(100));
             unsafe { ENV = Some(Env { runtime, registry }); }
         });
         unsafe { ENV.as_ref().unwrap() }
     }
 
-    fn sum(args: Value) -> Pin<Box<dyn Future<Output=Result<Value, Error>> + Send>> {
+    struct Offset(i64);
+
+    fn raw_i64(raw: Option<&&RawValue>) -> Option<i64> {
+        raw.and_then(|raw| raw.get().parse().ok())
+    }
+
+    fn sum(_state: Arc<StateMap>, args: Box<RawValue>) -> Pin<Box<dyn Future<Output=Result<Value, Error>> + Send>> {
         Box::pin(async move {
-            let (a, b) = match args {
-                Value::Array(args) if args.len() >= 2 => {
-                    let a = args[0].as_i64();
-                    let b = args[1].as_i64();
-                    (a, b)
-                }
-                Value::Object(args) => {
-                    let a = args.get("a").and_then(Value::as_i64);
-                    let b = args.get("b").and_then(Value::as_i64);
-                    (a, b)
-                }
+            let (a, b) = match Params::parse(&args)? {
+                Params::Array(args) if args.len() >= 2 => (raw_i64(args.first()), raw_i64(args.get(1))),
+                Params::Object(args) => (raw_i64(args.get("a")), raw_i64(args.get("b"))),
                 _ => return Err(Error::invalid_params()),
             };
-            if a.is_some() || b.is_some() {
-                Ok(Value::from(a.unwrap() + b.unwrap()))
-            } else {
-                Err(Error::invalid_params())
+            match (a, b) {
+                (Some(a), Some(b)) => Ok(Value::from(a + b)),
+                _ => Err(Error::invalid_params()),
             }
         })
     }
 
+    fn offset_sum(state: Arc<StateMap>, args: Box<RawValue>) -> Pin<Box<dyn Future<Output=Result<Value, Error>> + Send>> {
+        Box::pin(async move {
+            let offset = match state.get::<Offset>() {
+                Some(offset) => offset.0,
+                None => return Err(Error::internal_error()),
+            };
+            let (a, b) = match Params::parse(&args)? {
+                Params::Array(args) if args.len() >= 2 => (raw_i64(args.first()), raw_i64(args.get(1))),
+                Params::Object(args) => (raw_i64(args.get("a")), raw_i64(args.get("b"))),
+                _ => return Err(Error::invalid_params()),
+            };
+            match (a, b) {
+                (Some(a), Some(b)) => Ok(Value::from(offset + a + b)),
+                _ => Err(Error::invalid_params()),
+            }
+        })
+    }
 
     #[test]
     fn test_by_position_parameter() {
@@ -232,10 +373,240 @@ mod tests {
         assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"error":{"code":-32700,"message":"Parse error"},"id":null,"jsonrpc":"2.0"}"#);
     }
 
+    #[test]
+    fn test_state_with_position_parameter() {
+        let req = br#"{"jsonrpc":"2.0","method":"offsetSum","params":[3,4],"id":1}"#;
+        let result = env().runtime.block_on(env().registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":107}"#);
+    }
+
+    #[test]
+    fn test_state_with_name_parameter() {
+        let req = br#"{"jsonrpc":"2.0","method":"offsetSum","params":{"a":3,"b":4},"id":1}"#;
+        let result = env().runtime.block_on(env().registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":107}"#);
+    }
+
     #[test]
     fn test_invalid_version() {
         let req = br#"{"jsonrpc":"2.1","method":"sum1","params":[3,4],"id":1.1}"#;
         let result = env().runtime.block_on(env().registry.call(req));
         assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"error":{"code":-32700,"message":"Parse error"},"id":null,"jsonrpc":"2.0"}"#);
     }
+
+    fn count_up(_state: Arc<StateMap>, args: Value, sink: crate::SubscriptionSink) -> Pin<Box<dyn Future<Output=Result<(), Error>> + Send>> {
+        Box::pin(async move {
+            let n = args.as_i64().ok_or_else(Error::invalid_params)?;
+            for i in 0..n {
+                sink.send(Value::from(i));
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_subscribe_and_unsubscribe() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register_subscription("countUp", "countUp.update", count_up);
+        registry.register_unsubscribe("unsubscribe");
+        let registry = Arc::new(registry);
+
+        runtime.block_on(async move {
+            let mut driver = crate::ConnectionDriver::new(registry);
+
+            let req = br#"{"jsonrpc":"2.0","method":"countUp","params":3,"id":1}"#;
+            let response = driver.call(req).await.unwrap();
+            let id = response["result"].clone();
+            assert_eq!(response["id"], Value::from(1));
+
+            for i in 0..3 {
+                let notification = driver.next_outbound().await.unwrap();
+                assert_eq!(notification["method"], "countUp.update");
+                assert_eq!(notification["params"]["subscription"], id);
+                assert_eq!(notification["params"]["result"], Value::from(i));
+            }
+
+            let req = to_string(&serde_json::json!({"jsonrpc": "2.0", "method": "unsubscribe", "params": id, "id": 2})).unwrap();
+            let response = driver.call(req.as_bytes()).await.unwrap();
+            assert_eq!(response, serde_json::json!({"jsonrpc": "2.0", "result": true, "id": 2}));
+
+            let req = to_string(&serde_json::json!({"jsonrpc": "2.0", "method": "unsubscribe", "params": id, "id": 3})).unwrap();
+            let response = driver.call(req.as_bytes()).await.unwrap();
+            assert_eq!(response, serde_json::json!({"jsonrpc": "2.0", "result": false, "id": 3}));
+        });
+    }
+
+    struct Multiplier(i64);
+
+    #[json_rpc]
+    fn scaled_sum(State(m): State<Multiplier>, a: i64, b: i64) -> Result<i64, Infallible> {
+        Ok(m.0 * (a + b))
+    }
+
+    #[test]
+    fn test_macro_state_mixed_with_positional_args() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register_method("scaledSum", scaled_sum);
+        registry.data(Multiplier(10));
+
+        let req = br#"{"jsonrpc":"2.0","method":"scaledSum","params":[3,4],"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":70}"#);
+    }
+
+    #[test]
+    fn test_macro_state_mixed_with_named_args() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register_method("scaledSum", scaled_sum);
+        registry.data(Multiplier(10));
+
+        let req = br#"{"jsonrpc":"2.0","method":"scaledSum","params":{"a":3,"b":4},"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":70}"#);
+    }
+
+    struct Calc;
+
+    #[json_rpc(namespace = "math", separator = "_")]
+    impl Calc {
+        fn add(a: i64, b: i64) -> Result<i64, Infallible> {
+            Ok(a + b)
+        }
+
+        #[rpc_name("multiply")]
+        fn mul(a: i64, b: i64) -> Result<i64, Infallible> {
+            Ok(a * b)
+        }
+    }
+
+    #[test]
+    fn test_namespace_and_separator_attributes() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register::<Calc>();
+
+        let req = br#"{"jsonrpc":"2.0","method":"math_add","params":[3,4],"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":7}"#);
+    }
+
+    #[test]
+    fn test_rpc_name_overrides_default_method_name() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register::<Calc>();
+
+        let req = br#"{"jsonrpc":"2.0","method":"multiply","params":[3,4],"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":12}"#);
+
+        let req = br#"{"jsonrpc":"2.0","method":"math_mul","params":[3,4],"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"error":{"code":-32601,"message":"Method not found"},"id":1,"jsonrpc":"2.0"}"#);
+    }
+
+    struct RejectBlocked;
+
+    impl Middleware for RejectBlocked {
+        fn on_call<'a>(&'a self, req: Request, next: Next) -> Pin<Box<dyn Future<Output=Option<Response>> + Send + 'a>> {
+            Box::pin(async move {
+                if req.method == "blocked" {
+                    (!req.id.is_notification()).then(|| Response::error(req.id.clone(), Error::custom(-32000, "rejected by middleware")))
+                } else {
+                    next.run(req).await
+                }
+            })
+        }
+    }
+
+    struct RewriteParams;
+
+    impl Middleware for RewriteParams {
+        fn on_call<'a>(&'a self, req: Request, next: Next) -> Pin<Box<dyn Future<Output=Option<Response>> + Send + 'a>> {
+            Box::pin(async move {
+                let params = RawValue::from_string("[10,20]".to_owned()).unwrap();
+                next.run(Request::new(req.method, params, req.id)).await
+            })
+        }
+    }
+
+    #[test]
+    fn test_middleware_can_rewrite_params() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register_method("sum", sum);
+        registry.register_middleware(Arc::new(RewriteParams));
+
+        let req = br#"{"jsonrpc":"2.0","method":"sum","params":[3,4],"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":30}"#);
+    }
+
+    struct RecordingMiddleware(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Middleware for RecordingMiddleware {
+        fn on_call<'a>(&'a self, req: Request, next: Next) -> Pin<Box<dyn Future<Output=Option<Response>> + Send + 'a>> {
+            let seen = self.0.clone();
+            Box::pin(async move {
+                seen.lock().unwrap().push(req.method.clone());
+                next.run(req).await
+            })
+        }
+    }
+
+    #[test]
+    fn test_middleware_runs_for_subscribe_and_unsubscribe() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register_subscription("countUp", "countUp.update", count_up);
+        registry.register_unsubscribe("unsubscribe");
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        registry.register_middleware(Arc::new(RecordingMiddleware(seen.clone())));
+        let registry = Arc::new(registry);
+
+        runtime.block_on(async move {
+            let mut driver = crate::ConnectionDriver::new(registry);
+
+            let req = br#"{"jsonrpc":"2.0","method":"countUp","params":1,"id":1}"#;
+            let response = driver.call(req).await.unwrap();
+            let id = response["result"].clone();
+            driver.next_outbound().await.unwrap();
+
+            let req = to_string(&serde_json::json!({"jsonrpc": "2.0", "method": "unsubscribe", "params": id, "id": 2})).unwrap();
+            driver.call(req.as_bytes()).await.unwrap();
+        });
+
+        assert_eq!(*seen.lock().unwrap(), vec!["countUp".to_string(), "unsubscribe".to_string()]);
+    }
+
+    #[test]
+    fn test_middleware_short_circuits() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register_method("blocked", sum);
+        registry.register_middleware(Arc::new(RejectBlocked));
+
+        let req = br#"{"jsonrpc":"2.0","method":"blocked","params":[3,4],"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"error":{"code":-32000,"message":"rejected by middleware"},"id":1,"jsonrpc":"2.0"}"#);
+    }
+
+    #[test]
+    fn test_middleware_passes_through_and_suppresses_notifications() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut registry = Registry::new();
+        registry.register_method("sum", sum);
+        registry.register_middleware(Arc::new(RejectBlocked));
+
+        let req = br#"{"jsonrpc":"2.0","method":"sum","params":[3,4],"id":1}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert_eq!(to_string(result.as_ref().unwrap()).unwrap(), r#"{"id":1,"jsonrpc":"2.0","result":7}"#);
+
+        let req = br#"{"jsonrpc":"2.0","method":"blocked","params":[3,4]}"#;
+        let result = runtime.block_on(registry.call(req));
+        assert!(result.is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::{Error, Registry, StateMap};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SubscriptionId(u64);
+
+pub type SubscribeMethod = fn(Arc<StateMap>, Value, SubscriptionSink) -> std::pin::Pin<Box<dyn std::future::Future<Output=Result<(), Error>> + Send>>;
+
+#[derive(Clone)]
+pub struct SubscriptionSink {
+    id: SubscriptionId,
+    method: &'static str,
+    outbound: UnboundedSender<Value>,
+}
+
+impl SubscriptionSink {
+    fn new(id: SubscriptionId, method: &'static str, outbound: UnboundedSender<Value>) -> Self {
+        Self { id, method, outbound }
+    }
+
+    pub fn id(&self) -> SubscriptionId {
+        self.id
+    }
+
+    /// Returns `false` if the connection is already gone.
+    pub fn send(&self, result: Value) -> bool {
+        let frame = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": self.method,
+            "params": {
+                "subscription": self.id,
+                "result": result,
+            },
+        });
+        self.outbound.send(frame).is_ok()
+    }
+}
+
+pub struct Connection {
+    outbound: UnboundedSender<Value>,
+    subscriptions: Mutex<HashMap<SubscriptionId, JoinHandle<()>>>,
+    next_id: AtomicU64,
+}
+
+impl Connection {
+    pub(crate) fn new_subscription(&self, method: &'static str) -> (SubscriptionId, SubscriptionSink) {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let sink = SubscriptionSink::new(id, method, self.outbound.clone());
+        (id, sink)
+    }
+
+    pub(crate) fn track(&self, id: SubscriptionId, task: JoinHandle<()>) {
+        self.subscriptions.lock().unwrap().insert(id, task);
+    }
+
+    pub(crate) fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        match self.subscriptions.lock().unwrap().remove(&id) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        for (_, task) in self.subscriptions.lock().unwrap().drain() {
+            task.abort();
+        }
+    }
+}
+
+/// Drives a single bidirectional connection, feeding inbound frames to the
+/// registry and yielding outbound notification frames from active subscriptions.
+pub struct ConnectionDriver {
+    registry: Arc<Registry>,
+    connection: Arc<Connection>,
+    outbound: UnboundedReceiver<Value>,
+}
+
+impl ConnectionDriver {
+    pub fn new(registry: Arc<Registry>) -> Self {
+        let (tx, rx) = unbounded_channel();
+        let connection = Arc::new(Connection {
+            outbound: tx,
+            subscriptions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        });
+        Self { registry, connection, outbound: rx }
+    }
+
+    pub async fn call(&self, req: &[u8]) -> Option<Value> {
+        self.registry.call_with_connection(&self.connection, req).await
+    }
+
+    pub async fn next_outbound(&mut self) -> Option<Value> {
+        self.outbound.recv().await
+    }
+}